@@ -4,26 +4,563 @@ use core::SegmentMeta;
 use core::META_FILEPATH;
 use error::TantivyError;
 use indexer::delete_queue::DeleteCursor;
+use indexer::merge_policy::{MergeCandidate, MergePolicy};
 use indexer::SegmentEntry;
+use roaring::RoaringBitmap;
+use std::collections::hash_map::{DefaultHasher, HashMap};
 use std::collections::hash_set::HashSet;
+use std::collections::VecDeque;
 use std::fmt::{self, Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Condvar;
+use std::sync::Mutex;
 use std::sync::RwLock;
-use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Arc, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
 use Result as TantivyResult;
 
+/// Number of background merge threads to run concurrently when none is
+/// specified.
+const DEFAULT_NUM_MERGE_THREADS: usize = 4;
+
+/// A single dispatched merge, queued up for a worker thread to run.
+type MergeJob = Box<dyn FnOnce() + Send>;
+
+/// Bounded worker pool backing `SegmentManager::schedule_merges`.
+///
+/// Spawns exactly `num_merge_threads` worker threads once, up front,
+/// that each loop pulling jobs off a shared queue for as long as the
+/// scheduler is alive. Dispatching a merge pushes a job onto that queue
+/// rather than spawning a fresh OS thread per merge, so a steady stream
+/// of small merges reuses the same `num_merge_threads` threads instead
+/// of continuously churning new ones.
+struct MergeScheduler {
+    job_sender: mpsc::Sender<MergeJob>,
+    workers: Vec<JoinHandle<()>>,
+    // Number of jobs dispatched but not yet finished (queued or
+    // running), so `join_all` can wait for the queue to drain without
+    // having to shut the workers down to do it.
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl MergeScheduler {
+    fn new(num_merge_threads: usize) -> MergeScheduler {
+        let (job_sender, job_receiver) = mpsc::channel::<MergeJob>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..num_merge_threads)
+            .map(|_| {
+                let job_receiver = Arc::clone(&job_receiver);
+                thread::spawn(move || loop {
+                    let job = {
+                        let job_receiver = job_receiver
+                            .lock()
+                            .expect("Failed to acquire lock on merge job queue.");
+                        job_receiver.recv()
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        // The sender side (this `MergeScheduler`) was
+                        // dropped: nothing left to do.
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        MergeScheduler {
+            job_sender,
+            workers,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Queues `job` for one of this pool's worker threads to run.
+    fn dispatch(&self, job: MergeJob) {
+        let (pending_count, _) = &*self.pending;
+        *pending_count
+            .lock()
+            .expect("Failed to acquire lock on merge scheduler.") += 1;
+        let pending = Arc::clone(&self.pending);
+        let job = Box::new(move || {
+            job();
+            let (pending_count, condvar) = &*pending;
+            let mut pending_count = pending_count
+                .lock()
+                .expect("Failed to acquire lock on merge scheduler.");
+            *pending_count -= 1;
+            if *pending_count == 0 {
+                condvar.notify_all();
+            }
+        });
+        // The workers only ever exit once `job_sender` itself has been
+        // dropped, which can't happen while `self` (and so this very
+        // `&self` call) is still alive.
+        self.job_sender
+            .send(job)
+            .expect("merge worker pool is alive");
+    }
+
+    /// Blocks until every job dispatched so far (queued or running) has
+    /// completed.
+    fn join_all(&self) {
+        let (pending_count, condvar) = &*self.pending;
+        let mut pending_count = pending_count
+            .lock()
+            .expect("Failed to acquire lock on merge scheduler.");
+        while *pending_count > 0 {
+            pending_count = condvar
+                .wait(pending_count)
+                .expect("Failed to wait on merge scheduler.");
+        }
+    }
+}
+
+impl Default for MergeScheduler {
+    fn default() -> MergeScheduler {
+        MergeScheduler::new(DEFAULT_NUM_MERGE_THREADS)
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        // Dropping `self.job_sender` (happens implicitly, but we don't
+        // control field drop order against `self.workers` otherwise)
+        // closes the queue, which is what lets every worker's `recv()`
+        // return `Err` and the loop above exit.
+        let (job_sender, _) = mpsc::channel::<MergeJob>();
+        let old_sender = std::mem::replace(&mut self.job_sender, job_sender);
+        drop(old_sender);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct SegmentLockState {
+    writer: bool,
+    readers: usize,
+}
+
+/// Per-segment read/write exclusivity, so that two operations on
+/// unrelated segments (two concurrent `soft_commit`s, a `soft_commit`
+/// racing an `end_merge`) don't serialize against each other just
+/// because they both go through the same `SegmentManager`.
+///
+/// The coarse `RwLock<SegmentRegisters>` remains the source of truth
+/// for segment membership and is only ever held for the brief
+/// structural mutation itself; this table is what a caller holds
+/// across the read-modify-write of a *single* segment's own state
+/// (its delete opstamp, its alive bitmap) so that two such operations
+/// against the same segment can't race each other.
+#[derive(Default)]
+struct SegmentLockTable {
+    locks: Mutex<HashMap<SegmentId, SegmentLockState>>,
+    condvar: Condvar,
+}
+
+impl SegmentLockTable {
+    /// Blocks until no reader or writer holds `segment_id`, then locks
+    /// it exclusively until the returned guard is dropped.
+    fn lock_for_write(&self, segment_id: SegmentId) -> SegmentWriteGuard {
+        let mut locks = self
+            .locks
+            .lock()
+            .expect("Failed to acquire lock on segment lock table.");
+        loop {
+            let is_free = {
+                let state = locks
+                    .entry(segment_id.clone())
+                    .or_insert_with(SegmentLockState::default);
+                !state.writer && state.readers == 0
+            };
+            if is_free {
+                locks
+                    .get_mut(&segment_id)
+                    .expect("just inserted above")
+                    .writer = true;
+                break;
+            }
+            locks = self
+                .condvar
+                .wait(locks)
+                .expect("Failed to wait on segment lock table.");
+        }
+        SegmentWriteGuard {
+            table: self,
+            segment_id,
+        }
+    }
+
+    /// Blocks until no writer holds `segment_id`, then registers one
+    /// more reader until the returned guard is dropped.
+    fn lock_for_read(&self, segment_id: SegmentId) -> SegmentReadGuard {
+        let mut locks = self
+            .locks
+            .lock()
+            .expect("Failed to acquire lock on segment lock table.");
+        loop {
+            let has_writer = {
+                let state = locks
+                    .entry(segment_id.clone())
+                    .or_insert_with(SegmentLockState::default);
+                state.writer
+            };
+            if !has_writer {
+                locks
+                    .get_mut(&segment_id)
+                    .expect("just inserted above")
+                    .readers += 1;
+                break;
+            }
+            locks = self
+                .condvar
+                .wait(locks)
+                .expect("Failed to wait on segment lock table.");
+        }
+        SegmentReadGuard {
+            table: self,
+            segment_id,
+        }
+    }
+}
+
+/// Exclusive hold on a single segment's state. See `SegmentLockTable`.
+struct SegmentWriteGuard<'a> {
+    table: &'a SegmentLockTable,
+    segment_id: SegmentId,
+}
+
+impl<'a> Drop for SegmentWriteGuard<'a> {
+    fn drop(&mut self) {
+        let mut locks = self
+            .table
+            .locks
+            .lock()
+            .expect("Failed to acquire lock on segment lock table.");
+        if let Some(state) = locks.get_mut(&self.segment_id) {
+            state.writer = false;
+            if state.readers == 0 {
+                locks.remove(&self.segment_id);
+            }
+        }
+        self.table.condvar.notify_all();
+    }
+}
+
+/// Shared hold on a single segment's state. See `SegmentLockTable`.
+struct SegmentReadGuard<'a> {
+    table: &'a SegmentLockTable,
+    segment_id: SegmentId,
+}
+
+impl<'a> Drop for SegmentReadGuard<'a> {
+    fn drop(&mut self) {
+        let mut locks = self
+            .table
+            .locks
+            .lock()
+            .expect("Failed to acquire lock on segment lock table.");
+        if let Some(state) = locks.get_mut(&self.segment_id) {
+            state.readers -= 1;
+            if state.readers == 0 && !state.writer {
+                locks.remove(&self.segment_id);
+            }
+        }
+        self.table.condvar.notify_all();
+    }
+}
+
+/// Identifier of a single merge operation, handed out by a
+/// `MergeOperationInventory` when a merge is registered.
+type MergeOpId = usize;
+
+/// Keeps track of which segments are currently locked in an in-flight
+/// merge, so that `get_mergeable_segments` never hands out a segment
+/// that is already being merged.
+///
+/// This is shared (via `Arc`) between the `SegmentManager` and every
+/// `MergeOperationGuard` it has handed out, so that a guard can clear
+/// its own entry on `Drop` regardless of where it ends up living.
+#[derive(Default)]
+struct MergeOperationInventory {
+    next_opstamp: AtomicUsize,
+    in_merge_segment_ids: Mutex<HashMap<MergeOpId, Vec<SegmentId>>>,
+}
+
+impl MergeOperationInventory {
+    /// Returns the set of segments currently locked by an active merge.
+    fn segment_ids(&self) -> HashSet<SegmentId> {
+        self.in_merge_segment_ids
+            .lock()
+            .expect("Failed to acquire lock on merge operation inventory.")
+            .values()
+            .flat_map(|segment_ids| segment_ids.iter().cloned())
+            .collect()
+    }
+
+    /// Atomically checks that none of `segment_ids` is already locked by
+    /// another in-flight merge and, if so, registers a new merge
+    /// operation locking them, returning a guard that releases the lock
+    /// when it is dropped. Returns `None` if any of `segment_ids` is
+    /// already locked.
+    ///
+    /// This must be a single atomic check-and-insert: a caller that
+    /// first calls `segment_ids()` to check for conflicts and only then
+    /// registers leaves a race window where two callers can both see a
+    /// segment as free and both start a merge on it.
+    fn try_register(self: &Arc<Self>, segment_ids: Vec<SegmentId>) -> Option<MergeOperationGuard> {
+        let mut in_merge_segment_ids = self
+            .in_merge_segment_ids
+            .lock()
+            .expect("Failed to acquire lock on merge operation inventory.");
+        let already_in_merge = in_merge_segment_ids
+            .values()
+            .flat_map(|locked_segment_ids| locked_segment_ids.iter())
+            .any(|locked_segment_id| segment_ids.contains(locked_segment_id));
+        if already_in_merge {
+            return None;
+        }
+        let op_id = self.next_opstamp.fetch_add(1, Ordering::SeqCst);
+        in_merge_segment_ids.insert(op_id, segment_ids.clone());
+        Some(MergeOperationGuard {
+            inventory: Arc::clone(self),
+            op_id,
+            segment_ids,
+        })
+    }
+
+    fn release(&self, op_id: MergeOpId) {
+        self.in_merge_segment_ids
+            .lock()
+            .expect("Failed to acquire lock on merge operation inventory.")
+            .remove(&op_id);
+    }
+}
+
+/// A handle on a single in-flight merge operation.
+///
+/// As long as this guard is alive, the segments it was created from are
+/// excluded from `get_mergeable_segments`. Dropping it (explicitly, or
+/// implicitly at the end of `end_merge`) releases the lock.
+pub struct MergeOperationGuard {
+    inventory: Arc<MergeOperationInventory>,
+    op_id: MergeOpId,
+    segment_ids: Vec<SegmentId>,
+}
+
+impl MergeOperationGuard {
+    pub fn segment_ids(&self) -> &[SegmentId] {
+        &self.segment_ids
+    }
+}
+
+impl Drop for MergeOperationGuard {
+    fn drop(&mut self) {
+        self.inventory.release(self.op_id);
+    }
+}
+
+/// Epoch at which a segment's files were placed on the pending-free
+/// queue, i.e. the reclamation-ordering equivalent of an op-stamp.
+type Epoch = usize;
+
+/// Defers deletion of a segment's files until every epoch guard that
+/// was live at or before the freeing epoch has been released.
+///
+/// This is what lets `SegmentManager` drop a segment from its
+/// registers (on merge completion, commit, or empty-segment cleanup)
+/// without racing a concurrent reader or merge that may still hold
+/// file handles opened against that segment.
+#[derive(Default)]
+struct ReclamationState {
+    current_epoch: AtomicUsize,
+    // Number of live `EpochGuard`s that were acquired at a given epoch.
+    active_epochs: Mutex<HashMap<Epoch, usize>>,
+    // Files freed at a given epoch, not yet known to be safe to delete.
+    pending_free: Mutex<HashMap<Epoch, HashSet<PathBuf>>>,
+}
+
+impl ReclamationState {
+    /// Pins the current epoch for the lifetime of the returned guard.
+    ///
+    /// Callers that keep file handles open past the `SegmentManager`
+    /// lock (an open searcher, an in-progress merge reading its
+    /// inputs) should hold this guard for as long as those handles
+    /// are alive.
+    fn acquire(&self) -> EpochGuard {
+        let epoch = self.current_epoch.load(Ordering::SeqCst);
+        *self
+            .active_epochs
+            .lock()
+            .expect("Failed to acquire lock on reclamation state.")
+            .entry(epoch)
+            .or_insert(0) += 1;
+        EpochGuard { state: self, epoch }
+    }
+
+    /// Places `files` on the pending-free queue, tagged with a fresh
+    /// epoch. The epoch counter only ever advances, so any guard
+    /// acquired from this point on cannot observe these files.
+    fn free_files(&self, files: HashSet<PathBuf>) {
+        if files.is_empty() {
+            return;
+        }
+        let epoch = self.current_epoch.fetch_add(1, Ordering::SeqCst);
+        self.pending_free
+            .lock()
+            .expect("Failed to acquire lock on reclamation state.")
+            .entry(epoch)
+            .or_insert_with(HashSet::new)
+            .extend(files);
+    }
+
+    fn min_active_epoch(&self) -> Option<Epoch> {
+        self.active_epochs
+            .lock()
+            .expect("Failed to acquire lock on reclamation state.")
+            .keys()
+            .cloned()
+            .min()
+    }
+
+    /// Returns the files that are still pending free, i.e. every file
+    /// freed at an epoch that some still-live guard could have
+    /// observed. Entries older than the oldest live guard are dropped,
+    /// as they are no longer anyone's concern here.
+    fn pending_free_files(&self) -> HashSet<PathBuf> {
+        let min_active_epoch = self.min_active_epoch();
+        let mut pending_free = self
+            .pending_free
+            .lock()
+            .expect("Failed to acquire lock on reclamation state.");
+        pending_free
+            .retain(|epoch, _| min_active_epoch.map_or(false, |min_epoch| *epoch >= min_epoch));
+        pending_free
+            .values()
+            .flat_map(|files| files.iter().cloned())
+            .collect()
+    }
+}
+
+/// Pins the reclamation epoch current at the time of acquisition. See
+/// `ReclamationState::acquire`.
+pub struct EpochGuard<'a> {
+    state: &'a ReclamationState,
+    epoch: Epoch,
+}
+
+impl<'a> Drop for EpochGuard<'a> {
+    fn drop(&mut self) {
+        let mut active_epochs = self
+            .state
+            .active_epochs
+            .lock()
+            .expect("Failed to acquire lock on reclamation state.");
+        if let Some(count) = active_epochs.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                active_epochs.remove(&self.epoch);
+            }
+        }
+    }
+}
+
+/// Monotonically increasing identifier of a `commit` call, analogous
+/// to the `N` in a `segments_N` descriptor file.
+pub type Generation = u64;
+
+/// How many past commit points `SegmentManager` keeps around, so that
+/// `rollback_to` has somewhere to roll back to.
+///
+/// This is an in-process rollback window only, not a crash-recovery
+/// mechanism: nothing here is ever written to disk, so all of this
+/// history is lost the moment the process restarts. A real
+/// `segments_N`-file-backed implementation, with one descriptor per
+/// generation actually persisted to the `Directory`, would be needed
+/// for rollback to survive a crash; this type only covers the
+/// in-memory case.
+const NUM_RETAINED_GENERATIONS: usize = 10;
+
+const COMMIT_POINT_MAGIC: u64 = 0x5441_4e54_4956_5953; // "TANTIVYS"
+const COMMIT_POINT_VERSION: u32 = 1;
+
+/// A retained commit generation: the segments that were live at that
+/// generation, kept only in process memory (see `NUM_RETAINED_GENERATIONS`).
+///
+/// `checksum` mirrors the magic/version/CRC footer a real `segments_N`
+/// file would carry to detect a torn write, but since `CommitPoint` is
+/// built fresh in memory and never round-tripped through bytes, nothing
+/// here can actually produce a mismatch today -- `is_valid` is kept so
+/// that a future on-disk reader has the same shape to validate against
+/// once one exists, not because it currently guards against anything.
+#[derive(Clone)]
+struct CommitPoint {
+    generation: Generation,
+    segment_metas: Vec<SegmentMeta>,
+    checksum: u64,
+}
+
+impl CommitPoint {
+    fn new(generation: Generation, segment_metas: Vec<SegmentMeta>) -> CommitPoint {
+        let checksum = Self::checksum(generation, &segment_metas);
+        CommitPoint {
+            generation,
+            segment_metas,
+            checksum,
+        }
+    }
+
+    /// Mirrors the magic + version + CRC footer that would guard a real
+    /// on-disk `segments_N` file against a torn write. See the note on
+    /// `CommitPoint` itself: in this in-memory implementation there is
+    /// no byte round-trip for a torn write to corrupt.
+    fn checksum(generation: Generation, segment_metas: &[SegmentMeta]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        COMMIT_POINT_MAGIC.hash(&mut hasher);
+        COMMIT_POINT_VERSION.hash(&mut hasher);
+        generation.hash(&mut hasher);
+        for segment_meta in segment_metas {
+            format!("{:?}", segment_meta).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.checksum == Self::checksum(self.generation, &self.segment_metas)
+    }
+}
+
+/// The alive-doc-id set resulting from deletes applied to a committed
+/// segment by a `soft_commit` that happened after the last `commit`.
+///
+/// Represented as a Roaring bitmap over the segment's doc-id space
+/// rather than a bare delete-opstamp, so that successive soft-commits
+/// against the same segment union their newly-deleted docs together
+/// instead of one replacing the other.
+#[derive(Clone)]
+struct FutureDeletes {
+    delete_opstamp: u64,
+    alive_bitmap: RoaringBitmap,
+}
+
+/// A segment handed out by `start_merge`, paired with the most
+/// advanced alive-doc-id set known for it.
+pub struct MergeCandidateSegment {
+    pub segment_entry: SegmentEntry,
+    /// `Some` if a `soft_commit` deleted documents from this segment
+    /// after the last `commit`; merging should read through this
+    /// bitmap rather than `segment_entry`'s own delete state, so those
+    /// deletes are not lost nor replayed a second time down the line.
+    pub alive_bitmap: Option<RoaringBitmap>,
+}
+
 #[derive(Default)]
 struct SegmentRegisters {
     uncommitted: SegmentRegister,
     committed: SegmentRegister,
-    // soft commits can advance committed segment to a future delete
-    // opstamp.
-    //
-    // In that case the same `SegmentId` can appear in both `committed`
-    // and in `committed_in_the_future`.
-    //
-    // TODO: which one should be considered for merges?
-    committed_in_the_future: SegmentRegister
 }
 
 /// The segment manager stores the list of segments
@@ -31,9 +568,29 @@ struct SegmentRegisters {
 ///
 /// It guarantees the atomicity of the
 /// changes (merges especially)
-#[derive(Default)]
 pub struct SegmentManager {
     registers: RwLock<SegmentRegisters>,
+    // Segments in `committed` that a `soft_commit` has advanced past
+    // their last-committed delete opstamp. The same `SegmentId` can
+    // therefore appear in both `committed` and `committed_in_the_future`;
+    // the latter always holds the more advanced alive-set.
+    //
+    // Kept out of `registers` on purpose: the map has its own `Mutex`,
+    // and `soft_commit`, `end_merge` and `alive_bitmap` additionally
+    // hold the segment's entry in `segment_locks` while they touch it;
+    // folding it into the coarse `RwLock<SegmentRegisters>` would force
+    // every `soft_commit` to take that lock for the whole
+    // read-modify-write, defeating the point of locking per-segment at
+    // all. `commit`, `rollback_to` and `start_merge` go through the
+    // map's own `Mutex` only, without any per-segment lock.
+    committed_in_the_future: Mutex<HashMap<SegmentId, FutureDeletes>>,
+    merging_segments: Arc<MergeOperationInventory>,
+    reclamation: ReclamationState,
+    delete_cursor: DeleteCursor,
+    next_generation: AtomicU64,
+    commit_log: Mutex<VecDeque<CommitPoint>>,
+    merge_scheduler: MergeScheduler,
+    segment_locks: SegmentLockTable,
 }
 
 impl Debug for SegmentManager {
@@ -47,33 +604,185 @@ impl Debug for SegmentManager {
     }
 }
 
-pub fn get_mergeable_segments(
-    in_merge_segment_ids: &HashSet<SegmentId>,
-    segment_manager: &SegmentManager,
-) -> (Vec<SegmentMeta>, Vec<SegmentMeta>) {
-    let registers_lock = segment_manager.read();
-    (
-        registers_lock
-            .committed
-            .get_mergeable_segments(in_merge_segment_ids),
-        registers_lock
-            .uncommitted
-            .get_mergeable_segments(in_merge_segment_ids),
-    )
-}
-
 impl SegmentManager {
     pub fn from_segments(
         segment_metas: Vec<SegmentMeta>,
         delete_cursor: &DeleteCursor,
     ) -> SegmentManager {
-        SegmentManager {
+        let segment_manager = SegmentManager {
             registers: RwLock::new(SegmentRegisters {
                 uncommitted: SegmentRegister::default(),
-                committed: SegmentRegister::new(segment_metas, delete_cursor),
-                committed_in_the_future: SegmentRegister::default()
+                committed: SegmentRegister::new(segment_metas.clone(), delete_cursor),
             }),
+            committed_in_the_future: Mutex::new(HashMap::new()),
+            merging_segments: Arc::default(),
+            reclamation: ReclamationState::default(),
+            delete_cursor: delete_cursor.clone(),
+            next_generation: AtomicU64::new(1),
+            commit_log: Mutex::new(VecDeque::new()),
+            merge_scheduler: MergeScheduler::default(),
+            segment_locks: SegmentLockTable::default(),
+        };
+        segment_manager.record_commit_point(segment_metas);
+        segment_manager
+    }
+
+    /// Overrides the number of concurrent background merges allowed by
+    /// `schedule_merges` (default `4`).
+    pub fn with_num_merge_threads(mut self, num_merge_threads: usize) -> SegmentManager {
+        self.merge_scheduler = MergeScheduler::new(num_merge_threads);
+        self
+    }
+
+    /// Appends a new commit point recording `segment_metas`, evicting
+    /// the oldest retained generation past `NUM_RETAINED_GENERATIONS`.
+    fn record_commit_point(&self, segment_metas: Vec<SegmentMeta>) -> Generation {
+        let generation = self.next_generation.fetch_add(1, Ordering::SeqCst);
+        let commit_point = CommitPoint::new(generation, segment_metas);
+        let mut commit_log = self
+            .commit_log
+            .lock()
+            .expect("Failed to acquire lock on commit log.");
+        commit_log.push_front(commit_point);
+        commit_log.truncate(NUM_RETAINED_GENERATIONS);
+        generation
+    }
+
+    /// Scans retained commit points newest-first and returns the
+    /// generation of the first one whose footer checksum validates.
+    ///
+    /// In this in-memory implementation every retained commit point
+    /// always validates (see the note on `CommitPoint`); this method
+    /// exists so callers go through the same "skip a half-written
+    /// latest generation" check that a real on-disk reader would need,
+    /// rather than assuming the newest retained generation is always
+    /// usable.
+    pub fn latest_valid_generation(&self) -> Option<Generation> {
+        self.commit_log
+            .lock()
+            .expect("Failed to acquire lock on commit log.")
+            .iter()
+            .find(|commit_point| commit_point.is_valid())
+            .map(|commit_point| commit_point.generation)
+    }
+
+    /// Restores the `committed` register to the state recorded at
+    /// `generation`, as long as that generation is still retained and
+    /// its checksum validates.
+    ///
+    /// This only rolls back within the current process's own commit
+    /// history (`NUM_RETAINED_GENERATIONS` deep, all in memory); it is
+    /// not crash recovery, and a generation from before the current
+    /// process started is never available here.
+    pub fn rollback_to(&self, generation: Generation) -> TantivyResult<()> {
+        let commit_point = {
+            let commit_log = self
+                .commit_log
+                .lock()
+                .expect("Failed to acquire lock on commit log.");
+            commit_log
+                .iter()
+                .find(|commit_point| commit_point.generation == generation)
+                .cloned()
+        };
+        let commit_point = commit_point.ok_or_else(|| {
+            TantivyError::InvalidArgument(format!(
+                "No commit point retained for generation {}.",
+                generation
+            ))
+        })?;
+        if !commit_point.is_valid() {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Commit point for generation {} is corrupted.",
+                generation
+            )));
         }
+        let mut registers_lock = self.write();
+        registers_lock.committed =
+            SegmentRegister::new(commit_point.segment_metas, &self.delete_cursor);
+        drop(registers_lock);
+        self.committed_in_the_future
+            .lock()
+            .expect("Failed to acquire lock on committed_in_the_future.")
+            .clear();
+        Ok(())
+    }
+
+    /// Returns the most advanced alive-doc-id set known for
+    /// `segment_id`, if a `soft_commit` has deleted documents from it
+    /// since the last `commit`.
+    pub fn alive_bitmap(&self, segment_id: &SegmentId) -> Option<RoaringBitmap> {
+        let _segment_guard = self.segment_locks.lock_for_read(segment_id.clone());
+        self.committed_in_the_future
+            .lock()
+            .expect("Failed to acquire lock on committed_in_the_future.")
+            .get(segment_id)
+            .map(|future_deletes| future_deletes.alive_bitmap.clone())
+    }
+
+    /// Pins the current reclamation epoch for as long as the returned
+    /// guard is alive. Hold this for the duration of any access to
+    /// segment files obtained through this `SegmentManager` that can
+    /// outlive the locks above (an open searcher, an in-progress
+    /// merge), so the garbage collector knows not to delete them out
+    /// from under you.
+    pub fn acquire_epoch_guard(&self) -> EpochGuard {
+        self.reclamation.acquire()
+    }
+
+    /// Returns the files that must not be handed to the garbage
+    /// collector yet: files belonging to segments currently tracked by
+    /// this manager, files freed from removed segments that some
+    /// still-live `EpochGuard` may still be referencing, and files
+    /// belonging to every generation `rollback_to` could still roll
+    /// back to.
+    pub fn protected_files(&self) -> HashSet<PathBuf> {
+        let mut files = self.reclamation.pending_free_files();
+        let registers_lock = self.read();
+        for segment_entry in registers_lock.committed.segment_entries() {
+            files.extend(segment_entry.meta().list_files());
+        }
+        for segment_entry in registers_lock.uncommitted.segment_entries() {
+            files.extend(segment_entry.meta().list_files());
+        }
+        drop(registers_lock);
+        // `committed_in_the_future` only carries alive-doc-id bitmaps
+        // keyed by segment id; it never references files of its own.
+        //
+        // Every retained `commit_log` entry is a generation `rollback_to`
+        // could still be asked to restore; a file that only belongs to
+        // an older retained generation isn't referenced by `committed`
+        // above, but the GC must still leave it alone or the rollback
+        // would silently come back pointing at missing files.
+        for commit_point in self
+            .commit_log
+            .lock()
+            .expect("Failed to acquire lock on commit log.")
+            .iter()
+        {
+            for segment_meta in &commit_point.segment_metas {
+                files.extend(segment_meta.list_files());
+            }
+        }
+        files
+    }
+
+    /// Returns the list of segment metas that are currently mergeable,
+    /// split between committed and uncommitted segments.
+    ///
+    /// Segments that are locked by an in-flight merge (tracked by this
+    /// `SegmentManager`'s own merge operation inventory) are excluded.
+    pub fn get_mergeable_segments(&self) -> (Vec<SegmentMeta>, Vec<SegmentMeta>) {
+        let in_merge_segment_ids = self.merging_segments.segment_ids();
+        let registers_lock = self.read();
+        (
+            registers_lock
+                .committed
+                .get_mergeable_segments(&in_merge_segment_ids),
+            registers_lock
+                .uncommitted
+                .get_mergeable_segments(&in_merge_segment_ids),
+        )
     }
 
     /// Returns all of the segment entries (committed or uncommitted)
@@ -115,73 +824,203 @@ impl SegmentManager {
     /// Deletes all empty segments
     fn remove_empty_segments(&self) {
         let mut registers_lock = self.write();
+        let mut freed_files = HashSet::new();
         registers_lock
             .committed
             .segment_entries()
             .iter()
             .filter(|segment| segment.meta().num_docs() == 0)
             .for_each(|segment| {
+                freed_files.extend(segment.meta().list_files());
                 registers_lock
                     .committed
                     .remove_segment(&segment.segment_id())
             });
+        self.reclamation.free_files(freed_files);
     }
 
+    /// Installs `segment_entries` as the new committed set and records a
+    /// new generation for it in one atomic step: the register swap and
+    /// `record_commit_point` both happen while `self.write()` is held,
+    /// so two concurrent `commit` calls can never install their
+    /// registers in one order while numbering their generations in the
+    /// other.
     pub fn commit(&self, segment_entries: Vec<SegmentEntry>) {
         let mut registers_lock = self.write();
+        let segment_metas: Vec<SegmentMeta> = segment_entries
+            .iter()
+            .map(|segment_entry| segment_entry.meta().clone())
+            .collect();
+        let new_files: HashSet<PathBuf> = segment_entries
+            .iter()
+            .flat_map(|segment_entry| segment_entry.meta().list_files())
+            .collect();
+        let mut freed_files = HashSet::new();
+        for old_register in &[&registers_lock.committed, &registers_lock.uncommitted] {
+            for segment_entry in old_register.segment_entries() {
+                freed_files.extend(
+                    segment_entry
+                        .meta()
+                        .list_files()
+                        .into_iter()
+                        .filter(|file| !new_files.contains(file)),
+                );
+            }
+        }
+        // Carry forward any future alive-set that is still more
+        // advanced than the incoming segment's own delete opstamp, so
+        // those deletes aren't replayed from scratch on the next
+        // `soft_commit`, nor silently dropped by this commit.
+        let mut committed_in_the_future = self
+            .committed_in_the_future
+            .lock()
+            .expect("Failed to acquire lock on committed_in_the_future.");
+        let mut carried_future_deletes = HashMap::new();
+        for segment_entry in &segment_entries {
+            let segment_id = segment_entry.segment_id();
+            if let Some(future_deletes) = committed_in_the_future.remove(&segment_id) {
+                if future_deletes.delete_opstamp > segment_entry.meta().delete_opstamp() {
+                    carried_future_deletes.insert(segment_id, future_deletes);
+                }
+            }
+        }
         registers_lock.committed.clear();
-        registers_lock.committed_in_the_future.clear();
         registers_lock.uncommitted.clear();
         for segment_entry in segment_entries {
-            registers_lock.committed.register_segment_entry(segment_entry);
+            registers_lock
+                .committed
+                .register_segment_entry(segment_entry);
         }
+        *committed_in_the_future = carried_future_deletes;
+        drop(committed_in_the_future);
+        self.reclamation.free_files(freed_files);
+        // Assign the new generation while `registers_lock` is still
+        // held, so two concurrent `commit`s can't install their
+        // registers in one order but record commit points in the other.
+        self.record_commit_point(segment_metas);
     }
 
-    pub fn soft_commit(&self, segment_entries: Vec<SegmentEntry>) {
-        let mut registers_lock = self.write();
-        for segment_entry in segment_entries {
+    /// Applies soft-commit deletes to already-committed segments.
+    ///
+    /// Each `(segment_entry, newly_deleted_docs)` pair is a committed
+    /// or uncommitted segment's updated state, paired with the doc ids
+    /// that just became deleted (empty if `segment_entry` is
+    /// uncommitted, or unchanged). Deletes against a committed segment
+    /// are unioned into its `committed_in_the_future` alive-bitmap
+    /// rather than replacing it, so a segment deleted from by two
+    /// successive soft-commits accumulates both.
+    pub fn soft_commit(&self, segment_entries: Vec<(SegmentEntry, RoaringBitmap)>) {
+        for (segment_entry, newly_deleted_docs) in segment_entries {
             let segment_id = segment_entry.segment_id();
-            if let Some(committed_segment_entry) = registers_lock.committed.get(&segment_id) {
+            // Hold this segment exclusively for the rest of the loop
+            // body, so a concurrent `soft_commit` or `end_merge` on
+            // the very same segment can't interleave with it; a
+            // `soft_commit` touching other segments isn't blocked by
+            // this at all, only the brief membership lock below is
+            // shared with them.
+            let _segment_guard = self.segment_locks.lock_for_write(segment_id.clone());
+            let committed_segment_entry = self.read().committed.get(&segment_id);
+            if let Some(committed_segment_entry) = committed_segment_entry {
                 // this is a committed segment.
-                if committed_segment_entry.meta().delete_opstamp() == segment_entry.meta().delete_opstamp() {
+                if committed_segment_entry.meta().delete_opstamp()
+                    == segment_entry.meta().delete_opstamp()
+                {
                     // Actually, there was no change made to the segment...No need to do anything.
                     continue;
                 }
-                // Our `segment_entry` is a commited in which *future* deletes (as in, sent after the last
-                // commit)
-                // Let's append it to a dedicated register for that.
-                registers_lock.committed_in_the_future.register_segment_entry(segment_entry);
-                // TODO make sure we use `committed_in_the_future` segments,
-                // when we `commit`, to avoid replaying deletes several times.
-
-            } else if registers_lock.uncommitted.get(&segment_id).is_some() {
-                // This will override our previous entry.
-                registers_lock.uncommitted.register_segment_entry(segment_entry);
+                // Our `segment_entry` carries *future* deletes (as in,
+                // sent after the last commit). Union them into the
+                // segment's alive-bitmap for that dedicated register,
+                // seeding it from every doc still alive as of the last
+                // commit the first time we see this segment.
+                let delete_opstamp = segment_entry.meta().delete_opstamp();
+                let max_doc = segment_entry.meta().max_doc();
+                let mut committed_in_the_future = self
+                    .committed_in_the_future
+                    .lock()
+                    .expect("Failed to acquire lock on committed_in_the_future.");
+                let future_deletes =
+                    committed_in_the_future
+                        .entry(segment_id)
+                        .or_insert_with(|| {
+                            // Seed from the docs still alive as of the last
+                            // commit, not every doc: `committed_segment_entry`
+                            // may already carry its own deletes, and those
+                            // must not be resurrected just because this is
+                            // the first soft-commit we've seen against it.
+                            let mut alive_bitmap = RoaringBitmap::new();
+                            alive_bitmap.insert_range(0..max_doc);
+                            if let Some(delete_bitset) = committed_segment_entry.delete_bitset() {
+                                for doc in 0..max_doc {
+                                    if delete_bitset.is_deleted(doc) {
+                                        alive_bitmap.remove(doc);
+                                    }
+                                }
+                            }
+                            FutureDeletes {
+                                delete_opstamp,
+                                alive_bitmap,
+                            }
+                        });
+                future_deletes.alive_bitmap -= &newly_deleted_docs;
+                future_deletes.delete_opstamp = delete_opstamp;
+            } else {
+                let mut registers_lock = self.write();
+                if registers_lock.uncommitted.get(&segment_id).is_some() {
+                    // This will override our previous entry.
+                    registers_lock
+                        .uncommitted
+                        .register_segment_entry(segment_entry);
+                }
             }
         }
     }
 
     /// Marks a list of segments as in merge.
     ///
-    /// Returns an error if some segments are missing, or if
-    /// the `segment_ids` are not either all committed or all
-    /// uncommitted.
-    pub fn start_merge(&self, segment_ids: &[SegmentId]) -> TantivyResult<Vec<SegmentEntry>> {
+    /// Returns an error if some segments are missing, if the
+    /// `segment_ids` are not either all committed or all uncommitted, or
+    /// if any of them is already locked by another in-flight merge.
+    ///
+    /// On success, also returns a `MergeOperationGuard` that locks
+    /// `segment_ids` out of `get_mergeable_segments` until it is
+    /// dropped (or passed to `end_merge`), so the same segment can
+    /// never be scheduled into two concurrent merges.
+    pub fn start_merge(
+        &self,
+        segment_ids: &[SegmentId],
+    ) -> TantivyResult<(Vec<MergeCandidateSegment>, MergeOperationGuard)> {
         let registers_lock = self.read();
-        let mut segment_entries = vec![];
+        let mut merge_candidates = vec![];
         if registers_lock.uncommitted.contains_all(segment_ids) {
             for segment_id in segment_ids {
                 let segment_entry = registers_lock.uncommitted
                     .get(segment_id)
                     .expect("Segment id not found {}. Should never happen because of the contains all if-block.");
-                segment_entries.push(segment_entry);
+                merge_candidates.push(MergeCandidateSegment {
+                    segment_entry,
+                    alive_bitmap: None,
+                });
             }
         } else if registers_lock.committed.contains_all(segment_ids) {
             for segment_id in segment_ids {
                 let segment_entry = registers_lock.committed
                     .get(segment_id)
                     .expect("Segment id not found {}. Should never happen because of the contains all if-block.");
-                segment_entries.push(segment_entry);
+                // Always feed the merge the most-advanced alive-set:
+                // if a soft-commit deleted docs from this segment
+                // since the last commit, that bitmap supersedes
+                // whatever `segment_entry`'s own meta knows about.
+                let alive_bitmap = self
+                    .committed_in_the_future
+                    .lock()
+                    .expect("Failed to acquire lock on committed_in_the_future.")
+                    .get(segment_id)
+                    .map(|future_deletes| future_deletes.alive_bitmap.clone());
+                merge_candidates.push(MergeCandidateSegment {
+                    segment_entry,
+                    alive_bitmap,
+                });
             }
         } else {
             let error_msg = "Merge operation sent for segments that are not \
@@ -189,19 +1028,49 @@ impl SegmentManager {
                 .to_string();
             return Err(TantivyError::InvalidArgument(error_msg));
         }
-        Ok(segment_entries)
+        let merge_operation = self
+            .merging_segments
+            .try_register(segment_ids.to_vec())
+            .ok_or_else(|| {
+                TantivyError::InvalidArgument(
+                    "One or more of these segments are already being merged.".to_string(),
+                )
+            })?;
+        Ok((merge_candidates, merge_operation))
     }
 
     pub fn add_segment(&self, segment_entry: SegmentEntry) {
         let mut registers_lock = self.write();
-        registers_lock.uncommitted.register_segment_entry(segment_entry);
+        registers_lock
+            .uncommitted
+            .register_segment_entry(segment_entry);
     }
 
+    /// Completes a merge started by `start_merge`, swapping the
+    /// `before_merge` segments out for `after_merge_segment_entry`.
+    ///
+    /// Consuming `merge_operation` releases its lock on the merged
+    /// segments once this function returns, whether or not a matching
+    /// register was found.
     pub fn end_merge(
         &self,
-        before_merge_segment_ids: &[SegmentId],
+        merge_operation: MergeOperationGuard,
         after_merge_segment_entry: SegmentEntry,
     ) {
+        let before_merge_segment_ids = merge_operation.segment_ids();
+        // Hold every input segment exclusively while we swap them out
+        // for the merged segment, so a concurrent `soft_commit`
+        // against one of them can't race the removal below. Lock them
+        // in a canonical order (rather than whatever order the caller
+        // passed `segment_ids` to `start_merge` in) so that two calls
+        // locking an overlapping set of segments can never deadlock by
+        // acquiring them in opposite orders.
+        let mut sorted_segment_ids = before_merge_segment_ids.to_vec();
+        sorted_segment_ids.sort();
+        let _segment_guards: Vec<_> = sorted_segment_ids
+            .iter()
+            .map(|segment_id| self.segment_locks.lock_for_write(segment_id.clone()))
+            .collect();
         let mut registers_lock = self.write();
         let target_register: &mut SegmentRegister = {
             if registers_lock
@@ -219,10 +1088,28 @@ impl SegmentManager {
                 return;
             }
         };
+        let mut freed_files = HashSet::new();
         for segment_id in before_merge_segment_ids {
+            if let Some(segment_entry) = target_register.get(segment_id) {
+                freed_files.extend(segment_entry.meta().list_files());
+            }
             target_register.remove_segment(segment_id);
         }
         target_register.register_segment_entry(after_merge_segment_entry);
+        drop(registers_lock);
+        // The merged-away segments no longer exist; any future-delete
+        // entry recorded against them would otherwise dangle forever,
+        // and `alive_bitmap` would keep reporting stale deletes for a
+        // segment id nothing references anymore.
+        let mut committed_in_the_future = self
+            .committed_in_the_future
+            .lock()
+            .expect("Failed to acquire lock on committed_in_the_future.");
+        for segment_id in before_merge_segment_ids {
+            committed_in_the_future.remove(segment_id);
+        }
+        drop(committed_in_the_future);
+        self.reclamation.free_files(freed_files);
     }
 
     pub fn committed_segment_metas(&self) -> Vec<SegmentMeta> {
@@ -230,4 +1117,236 @@ impl SegmentManager {
         let registers_lock = self.read();
         registers_lock.committed.segment_metas()
     }
+
+    /// Asks `merge_policy` for merge candidates among the currently
+    /// mergeable segments and dispatches each onto the bounded
+    /// background merge pool, running `execute_merge` on one of its
+    /// worker threads and calling `end_merge` with its result.
+    ///
+    /// Candidates are drawn separately from committed and uncommitted
+    /// segments, so a dispatched merge is always all-committed or
+    /// all-uncommitted, matching the invariant `start_merge` enforces.
+    /// Segments already locked by another in-flight merge are excluded
+    /// up front (via `get_mergeable_segments`). Every candidate found is
+    /// queued; the pool's fixed worker threads pick each one up as they
+    /// free up rather than this call blocking on, or dropping, anything
+    /// past the thread count.
+    pub fn schedule_merges<Policy, Execute>(
+        self: &Arc<Self>,
+        merge_policy: &Policy,
+        execute_merge: Execute,
+    ) where
+        Policy: MergePolicy,
+        Execute: Fn(Vec<MergeCandidateSegment>) -> SegmentEntry + Clone + Send + Sync + 'static,
+    {
+        let (committed, uncommitted) = self.get_mergeable_segments();
+        let mut merge_candidates = merge_policy.compute_merge_candidates(&committed);
+        merge_candidates.extend(merge_policy.compute_merge_candidates(&uncommitted));
+        for MergeCandidate(segment_ids) in merge_candidates {
+            let segment_manager = Arc::clone(self);
+            let execute_merge = execute_merge.clone();
+            self.merge_scheduler.dispatch(Box::new(move || {
+                match segment_manager.start_merge(&segment_ids) {
+                    Ok((merge_candidate_segments, merge_operation)) => {
+                        let merged_segment_entry = execute_merge(merge_candidate_segments);
+                        segment_manager.end_merge(merge_operation, merged_segment_entry);
+                    }
+                    Err(err) => {
+                        warn!("Failed to start scheduled merge: {:?}", err);
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Blocks until every merge dispatched by `schedule_merges` so far
+    /// has completed. Flushes and commits that need a final,
+    /// merge-free view of the segment set should call this first.
+    pub fn wait_merging_threads(&self) {
+        self.merge_scheduler.join_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexer::delete_queue::DeleteQueue;
+
+    fn test_delete_cursor() -> DeleteCursor {
+        DeleteQueue::new().cursor()
+    }
+
+    fn test_segment_entry(delete_cursor: &DeleteCursor, max_doc: u32) -> SegmentEntry {
+        let segment_meta = SegmentMeta::new(SegmentId::generate_random(), max_doc);
+        SegmentEntry::new(segment_meta, delete_cursor.clone(), None)
+    }
+
+    #[test]
+    fn test_segment_manager_commit_then_rollback() {
+        let delete_cursor = test_delete_cursor();
+        let segment_manager = SegmentManager::from_segments(vec![], &delete_cursor);
+
+        let segment_entry = test_segment_entry(&delete_cursor, 10);
+        let segment_meta = segment_entry.meta().clone();
+        segment_manager.commit(vec![segment_entry]);
+        let generation = segment_manager
+            .latest_valid_generation()
+            .expect("commit should have recorded a retained generation");
+
+        segment_manager.commit(vec![]);
+        assert!(segment_manager.committed_segment_metas().is_empty());
+
+        segment_manager
+            .rollback_to(generation)
+            .expect("rollback to a still-retained generation should succeed");
+        assert_eq!(
+            segment_manager.committed_segment_metas(),
+            vec![segment_meta]
+        );
+    }
+
+    #[test]
+    fn test_segment_manager_start_merge_rejects_concurrent_overlap() {
+        let delete_cursor = test_delete_cursor();
+        let segment_entry_a = test_segment_entry(&delete_cursor, 10);
+        let segment_entry_b = test_segment_entry(&delete_cursor, 10);
+        let segment_id_a = segment_entry_a.segment_id();
+        let segment_id_b = segment_entry_b.segment_id();
+        let segment_manager = SegmentManager::from_segments(
+            vec![
+                segment_entry_a.meta().clone(),
+                segment_entry_b.meta().clone(),
+            ],
+            &delete_cursor,
+        );
+
+        let (merge_candidates, merge_operation) = segment_manager
+            .start_merge(&[segment_id_a.clone(), segment_id_b.clone()])
+            .expect("both segments are committed and not yet locked by a merge");
+        assert_eq!(merge_candidates.len(), 2);
+
+        // A second merge over an overlapping segment set must be
+        // rejected while the first merge operation is still alive.
+        assert!(segment_manager
+            .start_merge(&[segment_id_a.clone()])
+            .is_err());
+
+        let merged_segment_entry = test_segment_entry(&delete_cursor, 20);
+        let merged_segment_meta = merged_segment_entry.meta().clone();
+        segment_manager.end_merge(merge_operation, merged_segment_entry);
+
+        assert_eq!(
+            segment_manager.committed_segment_metas(),
+            vec![merged_segment_meta]
+        );
+    }
+
+    #[test]
+    fn test_segment_manager_soft_commit_updates_alive_bitmap() {
+        let delete_cursor = test_delete_cursor();
+        let segment_entry = test_segment_entry(&delete_cursor, 10);
+        let segment_id = segment_entry.segment_id();
+        let segment_manager =
+            SegmentManager::from_segments(vec![segment_entry.meta().clone()], &delete_cursor);
+
+        assert!(segment_manager.alive_bitmap(&segment_id).is_none());
+
+        let updated_meta = segment_entry.meta().clone().with_delete_meta(1, 1);
+        let updated_entry = SegmentEntry::new(updated_meta, delete_cursor.clone(), None);
+        let mut newly_deleted_docs = RoaringBitmap::new();
+        newly_deleted_docs.insert(3);
+        segment_manager.soft_commit(vec![(updated_entry, newly_deleted_docs)]);
+
+        let alive_bitmap = segment_manager
+            .alive_bitmap(&segment_id)
+            .expect("soft_commit should have recorded a future-delete entry");
+        assert!(!alive_bitmap.contains(3));
+        assert!(alive_bitmap.contains(0));
+    }
+
+    #[test]
+    fn test_merge_operation_inventory_rejects_overlapping_segments() {
+        let inventory = Arc::new(MergeOperationInventory::default());
+        let segment_id_a = SegmentId::generate_random();
+        let segment_id_b = SegmentId::generate_random();
+        let segment_id_c = SegmentId::generate_random();
+        let first_merge = inventory
+            .try_register(vec![segment_id_a.clone(), segment_id_b.clone()])
+            .expect("first merge should be free to register");
+        // Overlaps with `first_merge` on `segment_id_b`: must be refused,
+        // not silently double-booked.
+        assert!(inventory
+            .try_register(vec![segment_id_b.clone(), segment_id_c.clone()])
+            .is_none());
+        // Disjoint from `first_merge`: must succeed.
+        assert!(inventory.try_register(vec![segment_id_c.clone()]).is_some());
+        drop(first_merge);
+        // Now that the first merge's guard dropped, `segment_id_b` is
+        // free again.
+        assert!(inventory.try_register(vec![segment_id_b]).is_some());
+    }
+
+    #[test]
+    fn test_segment_lock_table_write_excludes_concurrent_write() {
+        let table = Arc::new(SegmentLockTable::default());
+        let segment_id = SegmentId::generate_random();
+        let _write_guard = table.lock_for_write(segment_id.clone());
+        let table_clone = Arc::clone(&table);
+        let segment_id_clone = segment_id.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let join_handle = thread::spawn(move || {
+            let _write_guard = table_clone.lock_for_write(segment_id_clone);
+            tx.send(()).expect("receiver still alive");
+        });
+        // The background thread must not be able to acquire the write
+        // lock while we're still holding it.
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(50))
+            .is_err());
+        drop(_write_guard);
+        rx.recv_timeout(std::time::Duration::from_secs(5))
+            .expect("background thread should acquire the lock once released");
+        join_handle.join().expect("thread should not panic");
+    }
+
+    #[test]
+    fn test_segment_lock_table_allows_concurrent_reads() {
+        let table = SegmentLockTable::default();
+        let segment_id = SegmentId::generate_random();
+        let _read_guard_a = table.lock_for_read(segment_id.clone());
+        let _read_guard_b = table.lock_for_read(segment_id);
+        // Two readers of the same segment must both be able to proceed.
+    }
+
+    #[test]
+    fn test_merge_scheduler_reuses_worker_threads_and_drains_on_join() {
+        let scheduler = MergeScheduler::new(2);
+        let run_count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let run_count = Arc::clone(&run_count);
+            scheduler.dispatch(Box::new(move || {
+                run_count.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        // More jobs than worker threads must still all run to
+        // completion -- queued, not dropped, once the pool is busy.
+        scheduler.join_all();
+        assert_eq!(run_count.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn test_reclamation_state_keeps_files_pending_while_guard_is_live() {
+        let reclamation = ReclamationState::default();
+        let epoch_guard = reclamation.acquire();
+        let file = PathBuf::from("segment.store");
+        let mut files = HashSet::new();
+        files.insert(file.clone());
+        reclamation.free_files(files);
+        // A guard acquired before the free is still pinning that epoch:
+        // the file must remain protected.
+        assert!(reclamation.pending_free_files().contains(&file));
+        drop(epoch_guard);
+        // With no guard left pinning that epoch, the file is free to go.
+        assert!(!reclamation.pending_free_files().contains(&file));
+    }
 }